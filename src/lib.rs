@@ -14,6 +14,7 @@ use std::time::duration::Duration;
 use quack::{ Associative, ActOn, Action, GetFrom, Get, Pair };
 use std::cmp;
 use std::marker::{ PhantomData };
+use std::sync::mpsc::{ channel, Receiver, Sender, SendError };
 
 /// Required to use the event loop.
 pub trait Window {
@@ -30,6 +31,55 @@ pub trait Window {
 
     /// Polls event from window.
     fn poll_event(&mut self) -> Option<Self::Event>;
+
+    /// Attempts to set the swap interval, returning `true` if the
+    /// back-end honored the request.
+    ///
+    /// The default implementation does nothing and returns `false`;
+    /// back-ends that can throttle `swap_buffers` to the display
+    /// refresh should override this to act on `SwapInterval`.
+    fn set_swap_interval(&mut self, _interval: SwapInterval) -> bool {
+        false
+    }
+
+    /// Returns a thread-safe handle that wakes a thread blocked in
+    /// `wait_event`/`wait_event_timeout`, if the back-end supports one.
+    ///
+    /// The default implementation returns `None`, so `Proxy::send` falls
+    /// back to the receiving loop noticing the message the next time it
+    /// wakes up on its own. Back-ends that expose a native "wake the
+    /// event queue" primitive should override this.
+    fn wakeup_fn(&self) -> Option<Box<Fn() + Send>> {
+        None
+    }
+
+    /// Blocks the calling thread until an event is available.
+    ///
+    /// The default implementation falls back to polling in a short
+    /// sleep loop, for back-ends that only expose `poll_event`.
+    /// Back-ends with a native blocking call should override this.
+    fn wait_event(&mut self) -> Self::Event {
+        loop {
+            if let Some(x) = self.poll_event() { return x; }
+            sleep(Duration::milliseconds(1));
+        }
+    }
+
+    /// Blocks the calling thread until an event is available or
+    /// `timeout` elapses, whichever happens first.
+    ///
+    /// The default implementation falls back to polling in a short
+    /// sleep loop, for back-ends that only expose `poll_event`.
+    /// Back-ends with a native blocking call should override this.
+    fn wait_event_timeout(&mut self, timeout: Duration) -> Option<Self::Event> {
+        let start = clock_ticks::precise_time_ns();
+        let timeout_ns = cmp::max(timeout.num_nanoseconds().unwrap_or(0), 0) as u64;
+        loop {
+            if let Some(x) = self.poll_event() { return Some(x); }
+            if clock_ticks::precise_time_ns() - start >= timeout_ns { return None; }
+            sleep(Duration::milliseconds(1));
+        }
+    }
 }
 
 impl<T> Window for T
@@ -94,6 +144,22 @@ pub struct SwapBuffers;
 
 impl Sized for SwapBuffers {}
 
+/// Tells window to set the swap interval, used to request vsync.
+///
+/// `0` disables vsync, `1` synchronizes `swap_buffers` to the display
+/// refresh. Back-ends that do not support this ignore the request.
+///
+/// ~~~ignore
+/// use current::Action;
+///
+/// ...
+/// window.action(SwapInterval(1));
+/// ~~~
+#[derive(Copy)]
+pub struct SwapInterval(pub i32);
+
+impl Sized for SwapInterval {}
+
 /// Polls event from window.
 ///
 /// ~~~ignore
@@ -132,8 +198,30 @@ pub struct IdleArgs {
     pub dt: f64
 }
 
+/// A thread-safe handle for pushing custom `U` events into a running
+/// `Events` loop from another thread, and for waking it up if it is
+/// parked waiting for window events (see `LoopMode::Wait`).
+///
+/// Obtained by calling `events.proxy()`.
+pub struct Proxy<U> {
+    sender: Sender<U>,
+    wakeup: Option<Box<Fn() + Send>>,
+}
+
+impl<U> Proxy<U> {
+    /// Sends a custom event into the event loop, waking it up if it is
+    /// currently parked waiting for window events.
+    pub fn send(&self, event: U) -> Result<(), SendError<U>> {
+        let result = self.sender.send(event);
+        if let Some(ref wakeup) = self.wakeup {
+            wakeup();
+        }
+        result
+    }
+}
+
 /// Methods required to map from consumed event to emitted event.
-pub trait EventMap<I> {
+pub trait EventMap<I, U> {
     /// Creates a render event.
     fn render(args: RenderArgs) -> Self;
     /// Creates an update event.
@@ -142,6 +230,9 @@ pub trait EventMap<I> {
     fn input(args: I) -> Self;
     /// Creates an idle event.
     fn idle(IdleArgs) -> Self;
+    /// Creates a custom event, carrying an application-defined message
+    /// sent through a `Proxy`.
+    fn custom(U) -> Self;
 }
 
 /// Tells whether last emitted event was idle or not.
@@ -158,6 +249,24 @@ enum State {
     UpdateLoop(Idle),
     HandleEvents,
     Update,
+    Wait,
+}
+
+/// Settings that control how the event loop waits between events.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LoopMode {
+    /// Ticks update and render events at a fixed rate. The default.
+    Rate,
+    /// Blocks until input arrives or a redraw is requested. Fixed
+    /// updates still run on schedule, but frames render on demand.
+    Wait,
+}
+
+quack_set! {
+    events: Events[W, I, E, U]
+    fn (loop_mode: LoopMode) [] {
+        events.loop_mode = loop_mode;
+    }
 }
 
 /// The number of updates per second
@@ -168,7 +277,7 @@ enum State {
 pub struct Ups(pub u64);
 
 quack_set! {
-    events: Events[W, I, E]
+    events: Events[W, I, E, U]
     fn (ups: Ups) [] {
         let frames = ups.0;
         events.dt_update_in_ns = BILLION / frames;
@@ -176,6 +285,49 @@ quack_set! {
     }
 }
 
+/// The maximum number of fixed updates to run in a row before control
+/// returns to rendering.
+///
+/// Guards against the "spiral of death": past the cap, the owed
+/// backlog is dropped by clamping `last_update` forward to now.
+#[derive(Copy)]
+pub struct MaxUpdatesPerFrame(pub u64);
+
+quack_set! {
+    events: Events[W, I, E, U]
+    fn (max_updates: MaxUpdatesPerFrame) [] {
+        events.max_updates_per_frame = max_updates.0;
+    }
+}
+
+/// Runs the loop on a deterministic synthetic clock instead of the
+/// system clock, with no real sleeping, for reproducible `update`/
+/// `render` sequences.
+///
+/// This covers `LoopMode::Rate` only. In `LoopMode::Wait` the loop still
+/// blocks in the window's `wait_event_timeout`, whose default
+/// implementation reads the system clock and really sleeps, and the
+/// synthetic clock does not advance while it waits.
+#[derive(Copy)]
+pub struct Benchmark(pub bool);
+
+quack_set! {
+    events: Events[W, I, E, U]
+    fn (benchmark: Benchmark) [] {
+        if benchmark.0 {
+            events.time_source = Box::new(VirtualTimeSource { current_ns: events.last_update });
+        } else {
+            // The virtual clock can be arbitrarily far ahead of the
+            // system clock, so rebase rather than let a later
+            // `start_render - last_update` underflow.
+            let now = clock_ticks::precise_time_ns();
+            events.last_update = now;
+            events.last_frame = now;
+            events.time_source = Box::new(RealTimeSource);
+        }
+    }
+}
+
 /// The maximum number of frames per second
 ///
 /// The frame rate can be lower because the
@@ -185,12 +337,26 @@ quack_set! {
 pub struct MaxFps(pub u64);
 
 quack_set! {
-    this: Events[W, I, E]
+    this: Events[W, I, E, U]
     fn (max_fps: MaxFps) [] {
         this.dt_frame_in_ns = BILLION / max_fps.0;
     }
 }
 
+/// Whether to synchronize frame timing to the display's vsync, instead
+/// of `MaxFps`' software timer.
+///
+/// Back-ends that cannot honor `SwapInterval` fall back to `MaxFps`.
+#[derive(Copy)]
+pub struct Vsync(pub bool);
+
+quack_set! {
+    events: Events[W, I, E, U]
+    fn (vsync: Vsync) [] {
+        events.vsync = vsync.0;
+    }
+}
+
 /// An event loop iterator
 ///
 /// *Warning: Because the iterator polls events from the window back-end,
@@ -228,7 +394,7 @@ quack_set! {
 ///     }
 /// }
 /// ~~~
-pub struct Events<W, I, E> {
+pub struct Events<W, I, E, U> {
     window: W,
     state: State,
     last_update: u64,
@@ -236,23 +402,77 @@ pub struct Events<W, I, E> {
     dt_update_in_ns: u64,
     dt_frame_in_ns: u64,
     dt: f64,
+    loop_mode: LoopMode,
+    redraw_requested: bool,
+    vsync: bool,
+    vsync_active: bool,
+    vsync_applied: bool,
+    max_updates_per_frame: u64,
+    updates_this_frame: u64,
+    time_source: Box<TimeSource>,
+    sender: Sender<U>,
+    receiver: Receiver<U>,
     _marker_i: PhantomData<I>,
     _marker_e: PhantomData<E>,
 }
 
+/// A source of monotonic time for the event loop, factored out so the
+/// timing behind `next()` can be swapped for a deterministic virtual
+/// clock (see `Benchmark`).
+trait TimeSource {
+    /// Returns the current time, in nanoseconds.
+    fn now_ns(&mut self) -> u64;
+    /// Waits for `duration` to pass. A virtual clock advances itself by
+    /// `duration` instead of actually blocking.
+    fn sleep(&mut self, duration: Duration);
+}
+
+/// The default `TimeSource`, backed by the system's monotonic clock.
+struct RealTimeSource;
+
+impl TimeSource for RealTimeSource {
+    fn now_ns(&mut self) -> u64 {
+        clock_ticks::precise_time_ns()
+    }
+    fn sleep(&mut self, duration: Duration) {
+        sleep(duration);
+    }
+}
+
+/// A deterministic `TimeSource` used by `Benchmark` mode: it never reads
+/// the system clock and never actually sleeps, instead advancing by
+/// however long the loop asked to wait.
+struct VirtualTimeSource {
+    current_ns: u64,
+}
+
+impl TimeSource for VirtualTimeSource {
+    fn now_ns(&mut self) -> u64 {
+        self.current_ns
+    }
+    fn sleep(&mut self, duration: Duration) {
+        let ns = cmp::max(duration.num_nanoseconds().unwrap_or(0), 0) as u64;
+        self.current_ns += ns;
+    }
+}
+
 static BILLION: u64 = 1_000_000_000;
 
 /// The default updates per second.
 pub const DEFAULT_UPS: Ups = Ups(120);
 /// The default maximum frames per second.
 pub const DEFAULT_MAX_FPS: MaxFps = MaxFps(60);
+/// The default maximum number of fixed updates per frame (no cap).
+pub const DEFAULT_MAX_UPDATES_PER_FRAME: MaxUpdatesPerFrame =
+    MaxUpdatesPerFrame(::std::u64::MAX);
 
-impl<W, I, E> Events<W, I, E> {
+impl<W, I, E, U> Events<W, I, E, U> {
     /// Creates a new event iterator with default UPS and FPS settings.
-    pub fn new(window: W) -> Events<W, I, E> {
+    pub fn new(window: W) -> Events<W, I, E, U> {
         let start = clock_ticks::precise_time_ns();
         let Ups(updates_per_second) = DEFAULT_UPS;
         let MaxFps(max_frames_per_second) = DEFAULT_MAX_FPS;
+        let (sender, receiver) = channel();
         Events {
             window: window,
             state: State::Render,
@@ -261,18 +481,72 @@ impl<W, I, E> Events<W, I, E> {
             dt_update_in_ns: BILLION / updates_per_second,
             dt_frame_in_ns: BILLION / max_frames_per_second,
             dt: 1.0 / updates_per_second as f64,
+            loop_mode: LoopMode::Rate,
+            redraw_requested: false,
+            vsync: false,
+            vsync_active: false,
+            vsync_applied: false,
+            max_updates_per_frame: DEFAULT_MAX_UPDATES_PER_FRAME.0,
+            updates_this_frame: 0,
+            time_source: Box::new(RealTimeSource),
+            sender: sender,
+            receiver: receiver,
             _marker_i: PhantomData,
             _marker_e: PhantomData,
         }
     }
+
+    /// Requests a redraw on the next iteration of the event loop.
+    ///
+    /// Only has an effect in `LoopMode::Wait`; in `LoopMode::Rate`
+    /// frames are already rendered continuously.
+    pub fn request_redraw(&mut self) {
+        self.redraw_requested = true;
+    }
+
+    /// Picks the state to idle in between frames, depending on `loop_mode`.
+    fn next_idle_state(&self) -> State {
+        match self.loop_mode {
+            LoopMode::Rate => State::UpdateLoop(Idle::No),
+            LoopMode::Wait => State::Wait,
+        }
+    }
+}
+
+impl<W, I, E, U> Events<W, I, E, U>
+    where
+        W: Window<Event = I>,
+{
+    /// Applies the requested vsync setting to the window, if it has
+    /// changed since the last time it was applied.
+    fn sync_swap_interval(&mut self) {
+        if self.vsync && !self.vsync_applied {
+            self.vsync_active = self.window.set_swap_interval(SwapInterval(1));
+            self.vsync_applied = true;
+        } else if !self.vsync && self.vsync_applied {
+            self.window.set_swap_interval(SwapInterval(0));
+            self.vsync_active = false;
+            self.vsync_applied = false;
+        }
+    }
+
+    /// Creates a proxy that other threads can use to push custom `U`
+    /// events into this loop, and to wake it up if it is parked in
+    /// `LoopMode::Wait`.
+    pub fn proxy(&self) -> Proxy<U> {
+        Proxy {
+            sender: self.sender.clone(),
+            wakeup: self.window.wakeup_fn(),
+        }
+    }
 }
 
-impl<W, I, E>
+impl<W, I, E, U>
 Iterator
-for Events<W, I, E>
+for Events<W, I, E, U>
     where
         W: Window<Event = I>,
-        E: EventMap<I>,
+        E: EventMap<I, U>,
 {
     type Item = E;
 
@@ -283,7 +557,10 @@ for Events<W, I, E>
                 State::Render => {
                     if self.window.should_close() { return None; }
 
-                    let start_render = clock_ticks::precise_time_ns();
+                    self.sync_swap_interval();
+                    self.updates_this_frame = 0;
+
+                    let start_render = self.time_source.now_ns();
                     self.last_frame = start_render;
 
                     let [w, h] = self.window.size();
@@ -299,27 +576,47 @@ for Events<W, I, E>
                         }));
                     }
 
-                    State::UpdateLoop(Idle::No)
+                    self.next_idle_state()
                 }
                 State::SwapBuffers => {
                     self.window.swap_buffers();
-                    State::UpdateLoop(Idle::No)
+                    self.next_idle_state()
                 }
                 State::UpdateLoop(ref mut idle) => {
-                    let current_time = clock_ticks::precise_time_ns();
-                    let next_frame = self.last_frame + self.dt_frame_in_ns;
+                    // Drain unconditionally, not just while idle, so a
+                    // sustained catch-up can't starve custom events.
+                    if let Ok(x) = self.receiver.try_recv() {
+                        *idle = Idle::No;
+                        return Some(EventMap::custom(x));
+                    }
+
+                    // Poll unconditionally too: with vsync active,
+                    // `next_event > current_time` below is never true,
+                    // which would otherwise starve window input almost
+                    // entirely while vsync is on.
+                    if let Some(x) = self.window.poll_event() {
+                        *idle = Idle::No;
+                        return Some(EventMap::input(x));
+                    }
+
+                    let current_time = self.time_source.now_ns();
+                    // With vsync active, `swap_buffers` paces the loop, so
+                    // render as soon as possible instead of waiting on the
+                    // `MaxFps` software timer.
+                    let next_frame = if self.vsync_active {
+                        current_time
+                    } else {
+                        self.last_frame + self.dt_frame_in_ns
+                    };
                     let next_update = self.last_update + self.dt_update_in_ns;
                     let next_event = cmp::min(next_frame, next_update);
                     if next_event > current_time {
-                        if let Some(x) = self.window.poll_event() {
-                            *idle = Idle::No;
-                            return Some(EventMap::input(x));
-                        } else if *idle == Idle::No {
+                        if *idle == Idle::No {
                             *idle = Idle::Yes;
                             let seconds = ((next_event - current_time) as f64) / (BILLION as f64);
                             return Some(EventMap::idle(IdleArgs { dt: seconds }))
                         }
-                        sleep( Duration::nanoseconds((next_event - current_time) as i64) );
+                        self.time_source.sleep(Duration::nanoseconds((next_event - current_time) as i64));
                         State::UpdateLoop(Idle::No)
                     } else if next_event == next_frame {
                         State::Render
@@ -335,11 +632,146 @@ for Events<W, I, E>
                     }
                 }
                 State::Update => {
-                    self.state = State::UpdateLoop(Idle::No);
                     self.last_update += self.dt_update_in_ns;
+                    self.updates_this_frame += 1;
+                    if self.updates_this_frame >= self.max_updates_per_frame {
+                        // We've owed too many updates in a row -- drop the
+                        // backlog instead of spiraling further behind, and
+                        // go render rather than catching up further.
+                        self.last_update = self.time_source.now_ns();
+                        self.state = State::Render;
+                    } else {
+                        self.state = self.next_idle_state();
+                    }
                     return Some(EventMap::update(UpdateArgs{ dt: self.dt }));
                 }
+                State::Wait => {
+                    if let Ok(x) = self.receiver.try_recv() {
+                        return Some(EventMap::custom(x));
+                    }
+                    if self.redraw_requested {
+                        self.redraw_requested = false;
+                        State::Render
+                    } else {
+                        let current_time = self.time_source.now_ns();
+                        let next_update = self.last_update + self.dt_update_in_ns;
+                        if next_update > current_time {
+                            // Not due yet -- block for at most the
+                            // remaining time, then run the update.
+                            match self.window.wait_event_timeout(
+                                Duration::nanoseconds((next_update - current_time) as i64)
+                            ) {
+                                Some(x) => return Some(EventMap::input(x)),
+                                None => State::Update,
+                            }
+                        } else {
+                            // Already due -- run it now rather than
+                            // blocking indefinitely in `wait_event`.
+                            State::Update
+                        }
+                    }
+                }
             };
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockWindow;
+
+    impl Window for MockWindow {
+        type Event = ();
+        fn should_close(&self) -> bool { false }
+        fn size(&self) -> [u32; 2] { [100, 100] }
+        fn swap_buffers(&mut self) {}
+        fn poll_event(&mut self) -> Option<()> { None }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum TestEvent {
+        Render,
+        Update,
+        Input,
+        Idle,
+        Custom,
+    }
+
+    impl EventMap<(), ()> for TestEvent {
+        fn render(_args: RenderArgs) -> Self { TestEvent::Render }
+        fn update(_args: UpdateArgs) -> Self { TestEvent::Update }
+        fn input(_args: ()) -> Self { TestEvent::Input }
+        fn idle(_args: IdleArgs) -> Self { TestEvent::Idle }
+        fn custom(_args: ()) -> Self { TestEvent::Custom }
+    }
+
+    #[test]
+    fn max_updates_per_frame_caps_the_catch_up() {
+        let mut events: Events<MockWindow, (), TestEvent, ()> = Events::new(MockWindow);
+        events.max_updates_per_frame = 3;
+        events.dt_update_in_ns = 1;
+        events.dt_frame_in_ns = 1_000_000_000;
+        events.last_update = 0;
+        events.last_frame = 0;
+        events.time_source = Box::new(VirtualTimeSource { current_ns: 1_000_000 });
+        events.state = State::UpdateLoop(Idle::No);
+
+        let seen: Vec<_> = (0..4).map(|_| events.next().unwrap()).collect();
+        assert_eq!(seen, vec![
+            TestEvent::Update, TestEvent::Update, TestEvent::Update, TestEvent::Render,
+        ]);
+    }
+
+    #[test]
+    fn disabling_benchmark_rebases_the_clock() {
+        let mut events: Events<MockWindow, (), TestEvent, ()> = Events::new(MockWindow);
+        events = events.set(Benchmark(true));
+        // Let the virtual clock race far ahead of the system clock, as
+        // it would after many iterations with no real sleeping.
+        events.last_update = 1_000_000_000_000;
+        events.last_frame = 1_000_000_000_000;
+
+        events = events.set(Benchmark(false));
+
+        // Switching back to the real clock must rebase last_update/
+        // last_frame, or `start_render - last_update` in State::Render
+        // would underflow and panic on the very next frame.
+        let now = clock_ticks::precise_time_ns();
+        assert!(events.last_update <= now);
+        assert!(events.last_frame <= now);
+    }
+
+    #[test]
+    fn wait_runs_a_due_update_instead_of_blocking() {
+        let mut events: Events<MockWindow, (), TestEvent, ()> = Events::new(MockWindow);
+        events.dt_update_in_ns = 1;
+        events.last_update = 0;
+        events.time_source = Box::new(VirtualTimeSource { current_ns: 1_000_000 });
+        events.state = State::Wait;
+
+        // The update is already overdue, so this must go straight to
+        // State::Update instead of blocking forever in wait_event_timeout.
+        assert_eq!(events.next(), Some(TestEvent::Update));
+    }
+
+    #[test]
+    fn proxy_send_is_not_starved_by_a_catch_up() {
+        let mut events: Events<MockWindow, (), TestEvent, ()> = Events::new(MockWindow);
+        events.max_updates_per_frame = 3;
+        events.dt_update_in_ns = 1;
+        events.dt_frame_in_ns = 1_000_000_000;
+        events.last_update = 0;
+        events.last_frame = 0;
+        events.time_source = Box::new(VirtualTimeSource { current_ns: 1_000_000 });
+        events.state = State::UpdateLoop(Idle::No);
+
+        events.proxy().send(()).unwrap();
+
+        // Even mid catch-up, the custom event surfaces on the very next
+        // pass instead of waiting for the backlog to drain.
+        assert_eq!(events.next(), Some(TestEvent::Custom));
+        assert_eq!(events.next(), Some(TestEvent::Update));
+    }
+}